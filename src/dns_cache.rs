@@ -0,0 +1,157 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::doh::{self, DenialOfExistence, DnssecState};
+use crate::Domain;
+
+/// A `doh::DnsResult`, minus the parts that are implied by how it's stored
+/// (the domain is the sled key) or recomputed on load (the remaining TTL).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    cnames: Vec<Domain>,
+    ips: Vec<std::net::IpAddr>,
+    ns: Vec<Domain>,
+    mx: Vec<(u16, Domain)>,
+    txt: Vec<String>,
+    dnssec: DnssecStateRepr,
+    dead: bool,
+    fetched_at: u64,
+    ttl: u64,
+}
+
+/// `doh::DnssecState` doesn't derive `Serialize`, so store its three states
+/// as a small enum of our own that round-trips through sled.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+enum DnssecStateRepr {
+    Secure,
+    Insecure,
+    Bogus,
+}
+
+impl From<DnssecState> for DnssecStateRepr {
+    fn from(state: DnssecState) -> Self {
+        match state {
+            DnssecState::Secure => DnssecStateRepr::Secure,
+            DnssecState::Insecure => DnssecStateRepr::Insecure,
+            DnssecState::Bogus => DnssecStateRepr::Bogus,
+        }
+    }
+}
+
+impl From<DnssecStateRepr> for DnssecState {
+    fn from(state: DnssecStateRepr) -> Self {
+        match state {
+            DnssecStateRepr::Secure => DnssecState::Secure,
+            DnssecStateRepr::Insecure => DnssecState::Insecure,
+            DnssecStateRepr::Bogus => DnssecState::Bogus,
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Clamps `ttl` into `[min, max]`, the way a validating resolver bounds the
+/// TTLs it's willing to cache a record for.
+fn clamp_ttl(ttl: u64, min: u64, max: u64) -> u64 {
+    ttl.clamp(min, max)
+}
+
+fn cache_key(domain: &Domain) -> String {
+    format!("dns-cache:{}", domain)
+}
+
+/// Reads a cached `doh::DnsResult` for `domain` out of `db`, if one exists
+/// and hasn't passed `fetched_at + ttl`.
+pub fn get(db: &sled::Db, domain: &Domain) -> Option<doh::DnsResult> {
+    let bytes = db.get(cache_key(domain)).ok().flatten()?;
+    let entry: CacheEntry = bincode::deserialize(&bytes).ok()?;
+    let now = now_secs();
+    if now >= entry.fetched_at + entry.ttl {
+        return None;
+    }
+    Some(doh::DnsResult {
+        cnames: entry.cnames,
+        ips: entry.ips,
+        ns: entry.ns,
+        mx: entry.mx,
+        txt: entry.txt,
+        dnssec: entry.dnssec.into(),
+        denial_of_existence: if entry.dead {
+            DenialOfExistence::Proven
+        } else {
+            DenialOfExistence::Unproven
+        },
+        min_ttl: entry.fetched_at + entry.ttl - now,
+    })
+}
+
+/// Writes `result` to the cache, clamping its TTL into the configured
+/// positive or negative window depending on whether it resolved to any
+/// records.
+pub fn put(
+    db: &sled::Db,
+    domain: &Domain,
+    result: &doh::DnsResult,
+    positive_ttl_bounds: (u64, u64),
+    negative_ttl_bounds: (u64, u64),
+) -> sled::Result<()> {
+    let dead = result.denial_of_existence == DenialOfExistence::Proven;
+    let is_negative = dead || (result.cnames.is_empty() && result.ips.is_empty());
+    let (min, max) = if is_negative {
+        negative_ttl_bounds
+    } else {
+        positive_ttl_bounds
+    };
+    let entry = CacheEntry {
+        cnames: result.cnames.clone(),
+        ips: result.ips.clone(),
+        ns: result.ns.clone(),
+        mx: result.mx.clone(),
+        txt: result.txt.clone(),
+        dnssec: result.dnssec.into(),
+        dead,
+        fetched_at: now_secs(),
+        ttl: clamp_ttl(result.min_ttl, min, max),
+    };
+    let bytes = bincode::serialize(&entry).expect("CacheEntry is always serializable");
+    db.insert(cache_key(domain), bytes)?;
+    Ok(())
+}
+
+/// Resolves `domain` through `server`, reusing a live cache entry when one
+/// exists and writing the fresh answer back to `db` otherwise.
+pub async fn lookup_domain(
+    db: &sled::Db,
+    server: &str,
+    timeout: Duration,
+    domain: &Domain,
+    positive_ttl_bounds: (u64, u64),
+    negative_ttl_bounds: (u64, u64),
+) -> Result<Option<doh::DnsResult>, hickory_resolver::error::ResolveError> {
+    if let Some(cached) = get(db, domain) {
+        return Ok(Some(cached));
+    }
+    let result = doh::lookup_domain(server, 3, timeout, domain).await?;
+    if let Some(result) = &result {
+        let _ = put(db, domain, result, positive_ttl_bounds, negative_ttl_bounds);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamp_ttl_bounds_into_the_configured_window() {
+        assert_eq!(clamp_ttl(10, 300, 86400), 300);
+        assert_eq!(clamp_ttl(999_999, 300, 86400), 86400);
+        assert_eq!(clamp_ttl(3600, 300, 86400), 3600);
+    }
+}
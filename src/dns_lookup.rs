@@ -1,120 +1,483 @@
-use std::str::FromStr;
-
-use std::io::BufRead;
-
-use async_std::fs::OpenOptions;
-use async_std::io::BufWriter;
-use async_std::prelude::*;
+use futures::stream::StreamExt;
+use rusqlite::{params, Connection};
 
+use crate::ip_reputation::IpReputationList;
 use crate::{doh, Domain};
 
-const DNS_RECORD_DIR: &'static str = "dns_db";
-const MAX_AGE: u64 = 7 * 86400;
+/// Where the SQLite-backed result cache lives, replacing the old flat,
+/// date-named files under `dns_db/`.
+const DNS_CACHE_PATH: &str = "dns_db.sqlite3";
 
 #[derive(Clone, Debug)]
 pub struct DNSResultRecord {
     pub domain: Domain,
     pub cnames: Vec<Domain>,
     pub ips: Vec<std::net::IpAddr>,
+    /// Delegated nameservers, if the NS lookup succeeded.
+    pub ns: Vec<Domain>,
+    /// Mail exchanges, as (preference, exchange) pairs, if the MX lookup
+    /// succeeded.
+    pub mx: Vec<(u16, Domain)>,
+    /// Raw TXT record strings, if the TXT lookup succeeded.
+    pub txt: Vec<String>,
+    /// DNSSEC validation state of this answer, as reported by the
+    /// hickory-resolver backed lookup.
+    pub dnssec: doh::DnssecState,
+    /// Set when the domain came back NXDOMAIN with an authenticated
+    /// NSEC/NSEC3 denial-of-existence proof, i.e. it is provably dead rather
+    /// than merely unanswered this round.
+    pub dead: bool,
+    /// When this record was fetched, as seconds since the Unix epoch.
+    pub fetched_at: u64,
+    /// How long this record may be trusted for, in seconds: the minimum
+    /// record TTL for a positive answer, or the SOA minimum for a negative
+    /// one, clamped into the configured bounds.
+    pub ttl: u64,
 }
 
 impl DNSResultRecord {
-    fn to_string(&self) -> String {
-        let mut output = String::new();
-        output.push_str(&self.domain);
-        output.push(';');
-        for cname in self.cnames.iter() {
-            output.push_str(&cname);
-            output.push(',');
-        }
-        output.push(';');
-        for ip in self.ips.iter() {
-            output.push_str(&ip.to_string());
-            output.push(',');
-        }
-        output
+    /// Whether this record's positive or negative answer is still within its
+    /// TTL, i.e. doesn't need re-querying yet.
+    pub fn is_live(&self, now: std::time::SystemTime) -> bool {
+        let now = now
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        now < self.fetched_at + self.ttl
     }
 }
 
-impl FromStr for DNSResultRecord {
-    type Err = ();
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut parts = s.split(';');
-        let domain: Domain = parts.next().ok_or(())?.parse().map_err(|_| ())?;
-        let mut cnames: Vec<Domain> = Vec::new();
-        for cname in parts.next().ok_or(())?.split(',').filter(|c| !c.is_empty()) {
-            cnames.push(cname.parse().map_err(|_| ())?)
-        }
-        let mut ips: Vec<std::net::IpAddr> = Vec::new();
-        for ip in parts
-            .next()
-            .ok_or(())?
-            .trim_end()
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Opens (creating if necessary) the SQLite cache and makes sure its one
+/// table exists. `domain` is the primary key, so a fresh lookup simply
+/// replaces whatever was cached for it before.
+fn open_db(path: &std::path::Path) -> rusqlite::Result<Connection> {
+    let conn = Connection::open(path)?;
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS dns_cache (
+            domain     TEXT PRIMARY KEY,
+            cnames     TEXT NOT NULL,
+            ips        TEXT NOT NULL,
+            ns         TEXT NOT NULL DEFAULT '',
+            mx         TEXT NOT NULL DEFAULT '',
+            txt        TEXT NOT NULL DEFAULT '',
+            dnssec     TEXT NOT NULL,
+            dead       INTEGER NOT NULL,
+            fetched_at INTEGER NOT NULL,
+            ttl        INTEGER NOT NULL
+        )",
+    )?;
+    // Databases created before NS/MX/TXT support won't have these columns;
+    // add them in place rather than forcing a fresh cache. Ignoring the
+    // error here is safe: it only ever fires once the columns already
+    // exist, which is exactly when we want to skip it.
+    for column in ["ns", "mx", "txt"] {
+        let _ = conn.execute_batch(&format!(
+            "ALTER TABLE dns_cache ADD COLUMN {column} TEXT NOT NULL DEFAULT ''"
+        ));
+    }
+    Ok(conn)
+}
+
+/// Deletes every row whose TTL ran out before `now`. A row expiring exactly
+/// at `now` is kept, so `load_cached` still checks `is_live` on what it reads.
+fn expire_stale(conn: &Connection, now: u64) -> rusqlite::Result<usize> {
+    conn.execute(
+        "DELETE FROM dns_cache WHERE fetched_at + ttl < ?1",
+        params![now as i64],
+    )
+}
+
+/// Joins `,` as the item separator and `:` as an inner field separator, for
+/// columns (`mx`) whose entries have more than one part.
+fn join_pairs(pairs: &[(u16, Domain)]) -> String {
+    pairs
+        .iter()
+        .map(|(preference, exchange)| format!("{preference}:{exchange}"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn parse_pairs(s: &str) -> Vec<(u16, Domain)> {
+    s.split(',')
+        .filter(|c| !c.is_empty())
+        .filter_map(|entry| {
+            let (preference, exchange) = entry.split_once(':')?;
+            Some((preference.parse().ok()?, exchange.parse().ok()?))
+        })
+        .collect()
+}
+
+fn row_to_record(row: &rusqlite::Row) -> rusqlite::Result<DNSResultRecord> {
+    let domain: String = row.get(0)?;
+    let cnames: String = row.get(1)?;
+    let ips: String = row.get(2)?;
+    let ns: String = row.get(3)?;
+    let mx: String = row.get(4)?;
+    let txt: String = row.get(5)?;
+    let dnssec: String = row.get(6)?;
+    let domain = domain.parse().map_err(|_| {
+        rusqlite::Error::InvalidColumnType(0, "domain".to_string(), rusqlite::types::Type::Text)
+    })?;
+    Ok(DNSResultRecord {
+        domain,
+        cnames: cnames
             .split(',')
             .filter(|c| !c.is_empty())
-        {
-            ips.push(ip.parse().map_err(|_| ())?)
+            .filter_map(|c| c.parse().ok())
+            .collect(),
+        ips: ips
+            .split(',')
+            .filter(|c| !c.is_empty())
+            .filter_map(|ip| ip.parse().ok())
+            .collect(),
+        ns: ns
+            .split(',')
+            .filter(|c| !c.is_empty())
+            .filter_map(|c| c.parse().ok())
+            .collect(),
+        mx: parse_pairs(&mx),
+        txt: txt.split(',').filter(|c| !c.is_empty()).map(str::to_string).collect(),
+        dnssec: match dnssec.as_str() {
+            "secure" => doh::DnssecState::Secure,
+            "bogus" => doh::DnssecState::Bogus,
+            _ => doh::DnssecState::Insecure,
+        },
+        dead: row.get::<_, i64>(7)? != 0,
+        fetched_at: row.get::<_, i64>(8)? as u64,
+        ttl: row.get::<_, i64>(9)? as u64,
+    })
+}
+
+/// Loads the still-live cached records for whichever of `domains` have one,
+/// keyed by domain rather than by which file happened to hold them.
+fn load_cached(
+    conn: &Connection,
+    domains: &std::collections::HashSet<Domain>,
+) -> rusqlite::Result<Vec<DNSResultRecord>> {
+    let mut stmt = conn.prepare(
+        "SELECT domain, cnames, ips, ns, mx, txt, dnssec, dead, fetched_at, ttl FROM dns_cache",
+    )?;
+    let now = std::time::SystemTime::now();
+    let rows = stmt.query_map([], row_to_record)?;
+    let mut live = Vec::new();
+    for row in rows {
+        let record = row?;
+        if domains.contains(&record.domain) && record.is_live(now) {
+            live.push(record);
         }
-        Ok(DNSResultRecord {
-            domain,
+    }
+    Ok(live)
+}
+
+/// Upserts a single resolution result, keyed by domain.
+fn store_record(conn: &Connection, record: &DNSResultRecord) -> rusqlite::Result<()> {
+    let cnames = record
+        .cnames
+        .iter()
+        .map(|d| d.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    let ips = record
+        .ips
+        .iter()
+        .map(|ip| ip.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    let ns = record
+        .ns
+        .iter()
+        .map(|d| d.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    let mx = join_pairs(&record.mx);
+    let txt = record.txt.join(",");
+    let dnssec = match record.dnssec {
+        doh::DnssecState::Secure => "secure",
+        doh::DnssecState::Insecure => "insecure",
+        doh::DnssecState::Bogus => "bogus",
+    };
+    conn.execute(
+        "INSERT INTO dns_cache (domain, cnames, ips, ns, mx, txt, dnssec, dead, fetched_at, ttl)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+         ON CONFLICT(domain) DO UPDATE SET
+            cnames = excluded.cnames,
+            ips = excluded.ips,
+            ns = excluded.ns,
+            mx = excluded.mx,
+            txt = excluded.txt,
+            dnssec = excluded.dnssec,
+            dead = excluded.dead,
+            fetched_at = excluded.fetched_at,
+            ttl = excluded.ttl",
+        params![
+            record.domain.to_string(),
             cnames,
             ips,
-        })
-    }
+            ns,
+            mx,
+            txt,
+            dnssec,
+            record.dead,
+            record.fetched_at as i64,
+            record.ttl as i64,
+        ],
+    )?;
+    Ok(())
+}
+
+/// A lookup that failed, carrying enough of its own context back through the
+/// `FuturesUnordered` set that the caller can decide whether to retry it.
+struct FailedLookup {
+    domain: Domain,
+    attempt: u32,
+    /// Index into `servers`/`buckets` of the server that just failed, so a
+    /// retry can be steered away from it instead of risking the same server
+    /// again right away.
+    server_idx: usize,
+    error: Box<dyn std::error::Error>,
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn get_dns_results(
-    client: &reqwest::Client,
     server: &str,
+    server_idx: usize,
+    timeout: std::time::Duration,
     domain: Domain,
-) -> Result<DNSResultRecord, Box<dyn std::error::Error>> {
-    Ok(doh::lookup_domain(&server, &client, 3, &domain)
-        .await?
-        .unwrap_or_else(|| DNSResultRecord {
-            domain: domain,
+    attempt: u32,
+    positive_ttl_bounds: (u64, u64),
+    negative_ttl_bounds: (u64, u64),
+) -> Result<DNSResultRecord, FailedLookup> {
+    let fetched_at = now_secs();
+    // Retries now happen one attempt at a time, a server rotation and a
+    // backoff apart, via the retry queue in `lookup_domains_with_ttl_bounds`
+    // rather than hammering the same server in a tight loop here.
+    match doh::lookup_domain(server, 1, timeout, &domain).await {
+        Ok(Some(result)) => {
+            let dead = result.denial_of_existence == doh::DenialOfExistence::Proven;
+            let is_negative = dead || (result.cnames.is_empty() && result.ips.is_empty());
+            let (min, max) = if is_negative {
+                negative_ttl_bounds
+            } else {
+                positive_ttl_bounds
+            };
+            Ok(DNSResultRecord {
+                domain,
+                cnames: result.cnames,
+                ips: result.ips,
+                ns: result.ns,
+                mx: result.mx,
+                txt: result.txt,
+                dnssec: result.dnssec,
+                dead,
+                fetched_at,
+                ttl: result.min_ttl.clamp(min, max),
+            })
+        }
+        Ok(None) => Ok(DNSResultRecord {
+            domain,
             cnames: Vec::new(),
             ips: Vec::new(),
-        }))
+            ns: Vec::new(),
+            mx: Vec::new(),
+            txt: Vec::new(),
+            dnssec: doh::DnssecState::Insecure,
+            dead: false,
+            fetched_at,
+            ttl: negative_ttl_bounds.0,
+        }),
+        Err(error) => Err(FailedLookup {
+            domain,
+            attempt,
+            server_idx,
+            error: error.into(),
+        }),
+    }
 }
 
-pub async fn lookup_domains<F>(
+/// Maximum number of attempts (including the first) before a lookup is
+/// recorded as a hard error instead of being requeued again.
+const MAX_LOOKUP_ATTEMPTS: u32 = 4;
+const RETRY_BASE_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+const RETRY_MAX_DELAY: std::time::Duration = std::time::Duration::from_secs(10);
+const MAX_IN_FLIGHT: usize = 500;
+
+/// Fallback per-server rate limit used when the caller doesn't hand in its
+/// own, chosen to stay well under what a public DoH provider will tolerate
+/// from a single client.
+pub(crate) const DEFAULT_RATE_LIMIT_QPS: f64 = 20.0;
+pub(crate) const DEFAULT_RATE_LIMIT_BURST: f64 = 40.0;
+/// A small pause between successive dispatches so a huge domain list ramps
+/// up against every resolver gradually instead of opening hundreds of
+/// connections to each of them in the same instant.
+pub(crate) const DEFAULT_DISPATCH_STAGGER: std::time::Duration = std::time::Duration::from_millis(5);
+
+/// Paces lookups against a single resolver: `burst` requests may go out
+/// immediately, refilling at `qps` per second up to that same cap, the way a
+/// client is expected to behave towards a public DoH endpoint.
+struct TokenBucket {
+    qps: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: std::time::Instant,
+}
+
+impl TokenBucket {
+    fn new(qps: f64, burst: f64) -> Self {
+        TokenBucket {
+            qps,
+            burst,
+            tokens: burst,
+            last_refill: std::time::Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: std::time::Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.qps).min(self.burst);
+        self.last_refill = now;
+    }
+
+    /// Takes a token if one is available right now.
+    fn try_take(&mut self, now: std::time::Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// How long until this bucket next has a token available.
+    fn available_in(&mut self, now: std::time::Instant) -> std::time::Duration {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            std::time::Duration::ZERO
+        } else {
+            std::time::Duration::from_secs_f64((1.0 - self.tokens) / self.qps)
+        }
+    }
+}
+
+/// Picks the server whose bucket will have a token soonest, waiting for it
+/// if that's not already now, and takes the token on its behalf. This
+/// replaces a blind `i % servers.len()` rotation: a resolver that's already
+/// at its rate limit is skipped in favour of one that isn't, rather than
+/// being dispatched to (and likely throttled) regardless.
+///
+/// `exclude` is the server a retried lookup just failed against: ties in
+/// `available_in` (every bucket idle, the common case) previously always
+/// resolved to the lowest index via `min_by_key`, so a retry could land right
+/// back on the server that just failed it instead of rotating to another
+/// one. It's ignored when there's only one server to pick from.
+async fn next_server(buckets: &mut [TokenBucket], exclude: Option<usize>) -> usize {
+    loop {
+        let now = std::time::Instant::now();
+        let single_server = buckets.len() == 1;
+        let (idx, wait) = buckets
+            .iter_mut()
+            .enumerate()
+            .filter(|(idx, _)| single_server || Some(*idx) != exclude)
+            .map(|(idx, bucket)| (idx, bucket.available_in(now)))
+            .min_by_key(|&(_, wait)| wait)
+            .expect("servers, and therefore buckets, is never empty");
+        if wait.is_zero() {
+            buckets[idx].try_take(std::time::Instant::now());
+            return idx;
+        }
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// A requeued lookup waiting for its backoff delay to elapse, ordered so a
+/// `BinaryHeap` pops the earliest-due entry first.
+struct RetryItem {
+    retry_at: std::time::Instant,
+    domain: Domain,
+    attempt: u32,
+    /// The server this lookup just failed against, so the retry dispatch
+    /// can steer `next_server` away from repeating it.
+    last_server: usize,
+}
+
+impl PartialEq for RetryItem {
+    fn eq(&self, other: &Self) -> bool {
+        self.retry_at == other.retry_at
+    }
+}
+impl Eq for RetryItem {}
+impl PartialOrd for RetryItem {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for RetryItem {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        // Reversed so the BinaryHeap (a max-heap) surfaces the soonest retry.
+        other.retry_at.cmp(&self.retry_at)
+    }
+}
+
+fn retry_delay(attempt: u32) -> std::time::Duration {
+    RETRY_BASE_DELAY
+        .saturating_mul(1u32 << attempt.min(8))
+        .min(RETRY_MAX_DELAY)
+}
+
+/// Bulk-resolves `domains`, with explicit positive/negative TTL clamp
+/// bounds, matching `config::Config`'s `[positive_min_ttl, positive_max_ttl]`
+/// and `[negative_min_ttl, negative_max_ttl]` settings, and an IP-reputation
+/// list: domains that resolve into one of its ranges (known-malicious
+/// hosting, sinkholes, ...) are logged and dropped from the `f` callback
+/// rather than passed through as an ordinary resolution. `rate_limit_qps`
+/// and `rate_limit_burst` bound each server's own token bucket, and
+/// `dispatch_stagger` is a fixed pause applied between dispatches so a large
+/// list ramps each resolver up gradually rather than all at once.
+#[allow(clippy::too_many_arguments)]
+pub async fn lookup_domains_with_ttl_bounds<F>(
     mut domains: std::collections::HashSet<Domain>,
     mut f: F,
 
     servers: &[String],
-    client: &reqwest::Client,
+    timeout: std::time::Duration,
+    positive_ttl_bounds: (u64, u64),
+    negative_ttl_bounds: (u64, u64),
+    ip_blocklist: &IpReputationList,
+    rate_limit_qps: f64,
+    rate_limit_burst: f64,
+    dispatch_stagger: std::time::Duration,
 ) -> Result<(), Box<dyn std::error::Error>>
 where
-    F: FnMut(&Domain, &[Domain], &[std::net::IpAddr]) -> (),
+    F: FnMut(&Domain, &[Domain], &[std::net::IpAddr], &[Domain], &[(u16, Domain)], &[String], bool) -> (),
 {
-    let _ = std::fs::create_dir(DNS_RECORD_DIR);
-    for entry in std::fs::read_dir(DNS_RECORD_DIR)? {
-        let entry = entry?;
-        let metadata = entry.metadata()?;
-        if let Ok(modified) = metadata.modified().or(metadata.created()) {
-            let now = std::time::SystemTime::now();
-            if let Ok(duration_since) = now.duration_since(modified) {
-                if duration_since.as_secs() < MAX_AGE {
-                    if let Ok(file) = std::fs::File::open(entry.path()) {
-                        let mut file = std::io::BufReader::new(file);
-                        let mut line = String::new();
-                        while let Ok(len) = file.read_line(&mut line) {
-                            if len == 0 {
-                                break;
-                            }
-                            if let Ok(record) = line.parse::<DNSResultRecord>() {
-                                domains.remove(&record.domain);
-                                f(&record.domain, &record.cnames, &record.ips)
-                            }
-                            line.clear();
-                        }
-                    }
-
-                    continue;
-                }
-            }
+    let conn = open_db(std::path::Path::new(DNS_CACHE_PATH))?;
+    // Records are expired individually against their own `ttl`, rather than
+    // discarding an entire file once its mtime crosses a fixed age: a domain
+    // that's legitimately had no records for a week shouldn't be re-queried
+    // just because another domain's row from the same day is also stale.
+    expire_stale(&conn, now_secs())?;
+    for record in load_cached(&conn, &domains)? {
+        domains.remove(&record.domain);
+        if ip_blocklist.contains_any(&record.ips) {
+            println!("Dropping {}: resolves into a blocklisted range", record.domain);
+        } else {
+            f(
+                &record.domain,
+                &record.cnames,
+                &record.ips,
+                &record.ns,
+                &record.mx,
+                &record.txt,
+                record.dead,
+            )
         }
-        println!("Removing expired record");
     }
 
     println!("Looking up {} domains", domains.len());
@@ -122,59 +485,188 @@ where
         return Ok(());
     }
 
-    let mut path = std::path::PathBuf::from(DNS_RECORD_DIR);
-    path.push(std::path::PathBuf::from(format!(
-        "{:?}",
-        chrono::Utc::today()
-    )));
-    let mut wtr = BufWriter::new(
-        OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(path)
-            .await?,
-    );
-    wtr.write_all(b"\n").await?;
     let total_length = domains.len();
     let mut domain_iter = domains.into_iter();
     let mut tasks = futures::stream::FuturesUnordered::new();
-    for (i, domain) in (0..500).zip(&mut domain_iter) {
+    let mut retry_queue: std::collections::BinaryHeap<RetryItem> = std::collections::BinaryHeap::new();
+    // One bucket per server so a resolver that's already at its rate limit
+    // is paced independently of the others, rather than a flat cap shared
+    // across every endpoint.
+    let mut buckets: Vec<TokenBucket> = servers
+        .iter()
+        .map(|_| TokenBucket::new(rate_limit_qps, rate_limit_burst))
+        .collect();
+
+    for domain in (&mut domain_iter).take(MAX_IN_FLIGHT) {
+        let server = next_server(&mut buckets, None).await;
+        tokio::time::sleep(dispatch_stagger).await;
         tasks.push(get_dns_results(
-            &client,
-            &servers[i % servers.len()],
+            &servers[server],
+            server,
+            timeout,
             domain,
+            0,
+            positive_ttl_bounds,
+            negative_ttl_bounds,
         ));
     }
-    let now = std::time::Instant::now();
-    let mut i = 0;
+
+    let started = std::time::Instant::now();
+    let mut completed = 0;
     let mut error_count = 0;
-    while let Some(record) = tasks.next().await {
-        if let Ok(record) = record {
-            if i % 100 == 0 {
-                println!(
-                    "{}/{} {}/s with {} errors: Got response for {}",
-                    i,
-                    total_length,
-                    i as f32 / now.elapsed().as_secs_f32(),
-                    error_count,
-                    &record.domain
-                );
+    loop {
+        while tasks.len() < MAX_IN_FLIGHT {
+            let ready_retry = match retry_queue.peek() {
+                Some(item) if item.retry_at <= std::time::Instant::now() => retry_queue.pop(),
+                _ => None,
+            };
+            if let Some(item) = ready_retry {
+                let server = next_server(&mut buckets, Some(item.last_server)).await;
+                tokio::time::sleep(dispatch_stagger).await;
+                tasks.push(get_dns_results(
+                    &servers[server],
+                    server,
+                    timeout,
+                    item.domain,
+                    item.attempt,
+                    positive_ttl_bounds,
+                    negative_ttl_bounds,
+                ));
+            } else if let Some(domain) = domain_iter.next() {
+                let server = next_server(&mut buckets, None).await;
+                tokio::time::sleep(dispatch_stagger).await;
+                tasks.push(get_dns_results(
+                    &servers[server],
+                    server,
+                    timeout,
+                    domain,
+                    0,
+                    positive_ttl_bounds,
+                    negative_ttl_bounds,
+                ));
+            } else {
+                break;
             }
-            f(&record.domain, &record.cnames, &record.ips);
-            wtr.write_all(record.to_string().as_bytes()).await?;
-            wtr.write_all(b"\n").await?;
-        } else {
-            error_count += 1;
         }
-        if let Some(next_domain) = domain_iter.next() {
-            tasks.push(get_dns_results(
-                &client,
-                &servers[i % servers.len()],
-                next_domain,
-            ));
-            i += 1;
+
+        if tasks.is_empty() {
+            match retry_queue.peek() {
+                Some(item) => {
+                    tokio::time::sleep_until(item.retry_at.into()).await;
+                    continue;
+                }
+                None => break,
+            }
+        }
+
+        match tasks.next().await {
+            Some(Ok(record)) => {
+                completed += 1;
+                if completed % 100 == 0 {
+                    println!(
+                        "{}/{} {}/s with {} errors: Got response for {}",
+                        completed,
+                        total_length,
+                        completed as f32 / started.elapsed().as_secs_f32(),
+                        error_count,
+                        &record.domain
+                    );
+                }
+                if ip_blocklist.contains_any(&record.ips) {
+                    println!("Dropping {}: resolves into a blocklisted range", record.domain);
+                } else {
+                    f(
+                        &record.domain,
+                        &record.cnames,
+                        &record.ips,
+                        &record.ns,
+                        &record.mx,
+                        &record.txt,
+                        record.dead,
+                    );
+                }
+                store_record(&conn, &record)?;
+            }
+            Some(Err(failed)) => {
+                if failed.attempt + 1 < MAX_LOOKUP_ATTEMPTS {
+                    retry_queue.push(RetryItem {
+                        retry_at: std::time::Instant::now() + retry_delay(failed.attempt),
+                        domain: failed.domain,
+                        attempt: failed.attempt + 1,
+                        last_server: failed.server_idx,
+                    });
+                } else {
+                    error_count += 1;
+                    println!(
+                        "Giving up on {} after {} attempts: {}",
+                        failed.domain, MAX_LOOKUP_ATTEMPTS, failed.error
+                    );
+                }
+            }
+            None => unreachable!("loop only awaits tasks.next() when tasks is non-empty"),
         }
     }
-    wtr.flush().await?;
     Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delay_backs_off_exponentially_and_caps() {
+        assert_eq!(retry_delay(0), RETRY_BASE_DELAY);
+        assert_eq!(retry_delay(1), RETRY_BASE_DELAY * 2);
+        assert_eq!(retry_delay(3), RETRY_BASE_DELAY * 8);
+        assert_eq!(retry_delay(20), RETRY_MAX_DELAY);
+    }
+
+    #[test]
+    fn token_bucket_limits_bursts_then_refills() {
+        let now = std::time::Instant::now();
+        let mut bucket = TokenBucket::new(10.0, 2.0);
+        assert!(bucket.try_take(now));
+        assert!(bucket.try_take(now));
+        assert!(!bucket.try_take(now));
+
+        let later = now + std::time::Duration::from_millis(150);
+        assert!(bucket.try_take(later));
+    }
+
+    #[test]
+    fn token_bucket_available_in_is_zero_once_refilled() {
+        let now = std::time::Instant::now();
+        let mut bucket = TokenBucket::new(10.0, 1.0);
+        assert!(bucket.try_take(now));
+        assert!(bucket.available_in(now) > std::time::Duration::ZERO);
+
+        let later = now + std::time::Duration::from_millis(200);
+        assert_eq!(bucket.available_in(later), std::time::Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn next_server_skips_the_excluded_server_on_a_retry() {
+        let mut buckets = vec![
+            TokenBucket::new(10.0, 1.0),
+            TokenBucket::new(10.0, 1.0),
+            TokenBucket::new(10.0, 1.0),
+        ];
+        // All buckets are equally idle, so without an exclusion the lowest
+        // index always wins the tie-break; a retry excluding it must land on
+        // a different server instead of repeating it.
+        assert_eq!(next_server(&mut buckets, Some(0)).await, 1);
+    }
+
+    #[tokio::test]
+    async fn next_server_ignores_the_exclusion_when_only_one_server_exists() {
+        let mut buckets = vec![TokenBucket::new(10.0, 1.0)];
+        assert_eq!(next_server(&mut buckets, Some(0)).await, 0);
+    }
+
+    #[test]
+    fn join_and_parse_pairs_round_trip() {
+        let pairs = vec![(10u16, "mx1.example.com".parse().unwrap()), (20, "mx2.example.com".parse().unwrap())];
+        let joined = join_pairs(&pairs);
+        assert_eq!(parse_pairs(&joined), pairs);
+    }
 }
\ No newline at end of file
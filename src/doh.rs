@@ -0,0 +1,321 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hickory_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use hickory_resolver::error::{ResolveError, ResolveErrorKind};
+use hickory_resolver::proto::rr::{Proof, Record, RecordType};
+use hickory_resolver::TokioAsyncResolver;
+use once_cell::sync::Lazy;
+
+use crate::Domain;
+
+/// The outcome of DNSSEC validation for a single query, mirroring the three
+/// states a validating resolver can return for a response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DnssecState {
+    /// The chain of trust validated down to a signed zone.
+    Secure,
+    /// The zone is unsigned (or the resolver wasn't asked to validate).
+    Insecure,
+    /// Signatures were present but failed to validate.
+    Bogus,
+}
+
+/// Whether a negative answer (NXDOMAIN / NODATA) is backed by an
+/// authenticated NSEC/NSEC3 denial-of-existence proof, as opposed to a bare,
+/// spoofable response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DenialOfExistence {
+    /// No proof was available or requested.
+    Unproven,
+    /// NSEC/NSEC3 records prove the name does not exist.
+    Proven,
+}
+
+/// Which record type a supplementary lookup is for, beyond the A/AAAA
+/// addresses every `lookup_domain` call already resolves. Domains sharing a
+/// nameserver or mail exchange are often operated together, which makes NS
+/// and MX answers useful clustering signal for blocklist work even when the
+/// addresses themselves differ.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum QueryType {
+    Ns,
+    Mx,
+    Txt,
+}
+
+#[derive(Clone, Debug)]
+pub struct DnsResult {
+    pub cnames: Vec<Domain>,
+    pub ips: Vec<IpAddr>,
+    /// Delegated nameservers, if the NS lookup succeeded.
+    pub ns: Vec<Domain>,
+    /// Mail exchanges, as (preference, exchange) pairs, if the MX lookup
+    /// succeeded.
+    pub mx: Vec<(u16, Domain)>,
+    /// Raw TXT record strings, if the TXT lookup succeeded.
+    pub txt: Vec<String>,
+    pub dnssec: DnssecState,
+    /// Set when the lookup came back NXDOMAIN and that absence could be
+    /// authenticated via NSEC/NSEC3, meaning the domain is provably dead
+    /// rather than merely unanswered.
+    pub denial_of_existence: DenialOfExistence,
+    /// The minimum TTL (seconds) across the records in this answer, or the
+    /// SOA minimum for a negative response. Callers use this to bound how
+    /// long the result may be cached.
+    pub min_ttl: u64,
+}
+
+static RESOLVERS: Lazy<dashmap::DashMap<String, Arc<TokioAsyncResolver>>> =
+    Lazy::new(dashmap::DashMap::new);
+
+/// Builds (and caches) a validating resolver for `server`, which may be a
+/// bare IP (plain UDP/TCP), a `tls://host` DoT address, or a `https://host/path`
+/// DoH address, matching the forms accepted by `config.get_dns_servers()`.
+async fn resolver_for(server: &str, timeout: Duration) -> Result<Arc<TokioAsyncResolver>, ResolveError> {
+    if let Some(existing) = RESOLVERS.get(server) {
+        return Ok(existing.clone());
+    }
+    let mut opts = ResolverOpts::default();
+    opts.timeout = timeout;
+    opts.validate = true;
+    let resolver = Arc::new(TokioAsyncResolver::tokio(server_config(server), opts));
+    RESOLVERS.insert(server.to_string(), resolver.clone());
+    Ok(resolver)
+}
+
+fn server_config(server: &str) -> ResolverConfig {
+    if let Some(rest) = server.strip_prefix("https://") {
+        let (host, _path) = rest.split_once('/').unwrap_or((rest, "dns-query"));
+        let group = NameServerConfigGroup::from_ips_https(&[], 443, host.to_string(), true);
+        return ResolverConfig::from_parts(None, vec![], group);
+    }
+    if let Some(host) = server.strip_prefix("tls://") {
+        let group = NameServerConfigGroup::from_ips_tls(&[], 853, host.to_string(), true);
+        return ResolverConfig::from_parts(None, vec![], group);
+    }
+    let ip = IpAddr::from_str(server).unwrap_or_else(|_| IpAddr::from([1, 1, 1, 1]));
+    let group = NameServerConfigGroup::from_ips_clear(&[ip], 53, true);
+    ResolverConfig::from_parts(None, vec![], group)
+}
+
+/// Resolves `domain` through `server`, requesting DNSSEC validation and, on
+/// NXDOMAIN, attempting to authenticate the denial via the NSEC/NSEC3
+/// records returned alongside it.
+pub async fn lookup_domain(
+    server: &str,
+    retries: usize,
+    timeout: Duration,
+    domain: &Domain,
+) -> Result<Option<DnsResult>, ResolveError> {
+    let resolver = resolver_for(server, timeout).await?;
+
+    let mut last_err = None;
+    for attempt in 0..retries.max(1) {
+        match resolve_once(&resolver, domain).await {
+            Ok(result) => return Ok(result),
+            Err(err) => {
+                last_err = Some(err);
+                if attempt + 1 < retries.max(1) {
+                    continue;
+                }
+            }
+        }
+    }
+    Err(last_err.expect("loop always runs at least once"))
+}
+
+async fn resolve_once(
+    resolver: &TokioAsyncResolver,
+    domain: &Domain,
+) -> Result<Option<DnsResult>, ResolveError> {
+    let lookup = match resolver.lookup_ip(domain.as_str()).await {
+        Ok(lookup) => lookup,
+        Err(err) => {
+            return match err.kind() {
+                ResolveErrorKind::NoRecordsFound { response_code, soa, .. } => {
+                    let denial = if *response_code == hickory_resolver::proto::op::ResponseCode::NXDomain
+                        && soa.as_deref().is_some_and(authenticated_denial)
+                    {
+                        DenialOfExistence::Proven
+                    } else {
+                        DenialOfExistence::Unproven
+                    };
+                    if *response_code == hickory_resolver::proto::op::ResponseCode::NXDomain
+                        || denial == DenialOfExistence::Proven
+                    {
+                        let min_ttl = soa
+                            .as_ref()
+                            .and_then(|soa| soa.data())
+                            .and_then(|data| data.as_soa())
+                            .map(|soa| soa.minimum() as u64)
+                            .unwrap_or(0);
+                        Ok(Some(DnsResult {
+                            cnames: Vec::new(),
+                            ips: Vec::new(),
+                            ns: Vec::new(),
+                            mx: Vec::new(),
+                            txt: Vec::new(),
+                            dnssec: DnssecState::Insecure,
+                            denial_of_existence: denial,
+                            min_ttl,
+                        }))
+                    } else {
+                        Ok(None)
+                    }
+                }
+                _ => Err(err),
+            };
+        }
+    };
+
+    let records = lookup.as_lookup().records();
+    let dnssec = dnssec_state_from_records(records);
+
+    let ips: Vec<IpAddr> = lookup.iter().collect();
+    let cnames: Vec<Domain> = records.iter().filter_map(cname_target).collect();
+    let min_ttl = records.iter().map(|r| r.ttl() as u64).min().unwrap_or(0);
+    let (ns, mx, txt) = lookup_extra(resolver, domain).await;
+
+    Ok(Some(DnsResult {
+        cnames,
+        ips,
+        ns,
+        mx,
+        txt,
+        dnssec,
+        denial_of_existence: DenialOfExistence::Unproven,
+        min_ttl,
+    }))
+}
+
+/// Best-effort NS/MX/TXT lookups alongside the primary A/AAAA query. Each is
+/// independent of the others, so a domain with no TXT record (say) still
+/// gets its NS and MX answers rather than the whole result being dropped.
+async fn lookup_extra(
+    resolver: &TokioAsyncResolver,
+    domain: &Domain,
+) -> (Vec<Domain>, Vec<(u16, Domain)>, Vec<String>) {
+    let ns = resolver
+        .ns_lookup(domain.as_str())
+        .await
+        .map(|lookup| {
+            lookup
+                .iter()
+                .filter_map(|name| name.to_utf8().parse().ok())
+                .collect()
+        })
+        .unwrap_or_else(|err| empty_on_error(QueryType::Ns, domain, &err));
+    let mx = resolver
+        .mx_lookup(domain.as_str())
+        .await
+        .map(|lookup| {
+            lookup
+                .iter()
+                .filter_map(|mx| mx.exchange().to_utf8().parse().ok().map(|exchange| (mx.preference(), exchange)))
+                .collect()
+        })
+        .unwrap_or_else(|err| empty_on_error(QueryType::Mx, domain, &err));
+    let txt = resolver
+        .txt_lookup(domain.as_str())
+        .await
+        .map(|lookup| {
+            lookup
+                .iter()
+                .map(|txt| {
+                    txt.txt_data()
+                        .iter()
+                        .map(|chunk| String::from_utf8_lossy(chunk))
+                        .collect::<String>()
+                })
+                .collect()
+        })
+        .unwrap_or_else(|err| empty_on_error(QueryType::Txt, domain, &err));
+    (ns, mx, txt)
+}
+
+/// A supplementary NS/MX/TXT lookup failing (no record, timeout, ...)
+/// shouldn't fail the whole resolution, so it's logged and treated as empty
+/// rather than propagated.
+fn empty_on_error<T>(query_type: QueryType, domain: &Domain, err: &ResolveError) -> Vec<T> {
+    println!("{:?} lookup for {} failed: {}", query_type, domain, err);
+    Vec::new()
+}
+
+fn cname_target(record: &Record) -> Option<Domain> {
+    if record.record_type() != RecordType::CNAME {
+        return None;
+    }
+    record
+        .data()?
+        .as_cname()
+        .and_then(|name| name.to_utf8().parse().ok())
+}
+
+/// Whether the SOA record accompanying a negative response was itself
+/// authenticated by the resolver's own DNSSEC validator.
+///
+/// `resolver_for` enables `ResolverOpts::validate`, which makes hickory walk
+/// the NSEC/NSEC3 closest-encloser and next-closer chain (including the
+/// wildcard-denial case) internally before a `NoRecordsFound` error ever
+/// reaches this module; `soa.proof()` only comes back `Proof::Secure` once
+/// that walk has confirmed the chain proves the name's non-existence.
+/// Deferring to it avoids re-deriving the RFC 5155 hashed-owner-name proof
+/// ourselves from records the resolver's lookup API doesn't expose anyway.
+fn authenticated_denial(soa: &Record) -> bool {
+    soa.proof() == Proof::Secure
+}
+
+/// Reads the validating resolver's own per-record DNSSEC proof rather than
+/// inferring validity from a response's `DNSClass` (IN/CH/HS/...), which
+/// says nothing about whether the chain of trust actually validated. Any
+/// record the resolver marked `Bogus` makes the whole answer bogus, even if
+/// others in the same response validated cleanly.
+fn dnssec_state_from_records(records: &[Record]) -> DnssecState {
+    records.iter().fold(DnssecState::Insecure, |state, record| {
+        match (state, record.proof()) {
+            (DnssecState::Bogus, _) | (_, Proof::Bogus) => DnssecState::Bogus,
+            (DnssecState::Secure, _) | (_, Proof::Secure) => DnssecState::Secure,
+            (state, _) => state,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record_with_proof(proof: Proof) -> Record {
+        let mut record = Record::new();
+        record.set_proof(proof);
+        record
+    }
+
+    #[test]
+    fn dnssec_state_defaults_to_insecure_for_an_empty_answer() {
+        assert_eq!(dnssec_state_from_records(&[]), DnssecState::Insecure);
+    }
+
+    #[test]
+    fn dnssec_state_is_secure_once_any_record_validates() {
+        let records = vec![record_with_proof(Proof::Insecure), record_with_proof(Proof::Secure)];
+        assert_eq!(dnssec_state_from_records(&records), DnssecState::Secure);
+    }
+
+    #[test]
+    fn dnssec_state_is_bogus_if_any_record_fails_validation_even_after_a_secure_one() {
+        // A single bogus record in the answer should poison the whole result,
+        // regardless of whether a secure record was folded in first.
+        let records = vec![record_with_proof(Proof::Secure), record_with_proof(Proof::Bogus)];
+        assert_eq!(dnssec_state_from_records(&records), DnssecState::Bogus);
+    }
+
+    #[test]
+    fn authenticated_denial_is_true_only_for_a_secure_proof() {
+        assert!(authenticated_denial(&record_with_proof(Proof::Secure)));
+        assert!(!authenticated_denial(&record_with_proof(Proof::Insecure)));
+        assert!(!authenticated_denial(&record_with_proof(Proof::Bogus)));
+    }
+}
@@ -15,6 +15,11 @@ pub struct DomainFilterBuilder {
     adblock: HashSet<String>,
     allow_regex: HashSet<String>,
     disallow_regex: HashSet<String>,
+    /// Adblock resource types, beyond the always-checked `document`, whose
+    /// rules should also count as blocking the whole domain (e.g. `script`,
+    /// `image`). A rule scoped to a type that's never checked here is
+    /// correctly left alone rather than treated as a blanket host block.
+    block_resource_types: HashSet<String>,
 }
 
 impl DomainFilterBuilder {
@@ -64,6 +69,13 @@ impl DomainFilterBuilder {
         self.adblock.insert(rule.to_string());
     }
 
+    /// Also treat rules scoped to `resource_type` (e.g. `script`, `image`) as
+    /// blocking the whole domain, in addition to the `document` type that's
+    /// always checked.
+    pub fn add_block_resource_type(&mut self, resource_type: &str) {
+        self.block_resource_types.insert(resource_type.to_string());
+    }
+
     pub fn add_allow_regex(&mut self, re: &str) {
         if regex::Regex::new(re).is_ok() {
             self.allow_regex.insert(re.to_string());
@@ -76,6 +88,14 @@ impl DomainFilterBuilder {
     }
 
     pub fn to_domain_filter(&self) -> DomainFilter {
+        // Go through a `FilterSet` rather than `Engine::from_rules` so that
+        // `$badfilter` rules cancel the rules they target before the engine
+        // is built, instead of being parsed as (inert) blocking rules.
+        let mut filter_set = adblock::lists::FilterSet::new(false);
+        filter_set.add_filters(
+            &self.adblock.iter().cloned().collect::<Vec<String>>(),
+            adblock::lists::ParseOptions::default(),
+        );
         DomainFilter {
             allow_domains: self.allow_domains.clone(),
             disallow_domains: self.disallow_domains.clone(),
@@ -85,15 +105,23 @@ impl DomainFilterBuilder {
             disallow_ips: self.disallow_ips.clone(),
             allow_ip_net: self.allow_ip_net.iter().cloned().collect(),
             disallow_ip_net: self.disallow_ip_net.iter().cloned().collect(),
-            adblock: adblock::engine::Engine::from_rules(
-                &self.adblock.iter().cloned().collect::<Vec<String>>(),
-            ),
+            adblock: adblock::engine::Engine::from_filter_set(filter_set, true),
             allow_regex: regex::RegexSet::new(&self.allow_regex).unwrap(),
             disallow_regex: regex::RegexSet::new(&self.disallow_regex).unwrap(),
+            block_resource_types: self.block_resource_types.clone(),
         }
     }
 }
 
+/// Stand-in "page" a candidate domain is treated as having been loaded from,
+/// for adblock rules that key off `$third-party`/`$first-party`/`$domain=`.
+/// blockconvert has no real page context for a bare domain, so rather than
+/// pass the candidate's own URL as both the request and the source (which
+/// would make every rule evaluate as first-party and never match a
+/// third-party-scoped rule), every candidate is conservatively treated as
+/// loaded from this unrelated page.
+const THIRD_PARTY_SOURCE: &str = "https://navigation.invalid";
+
 fn is_subdomain_of_list(domain: &Domain, filter_list: &std::collections::HashSet<Domain>) -> bool {
     domain
         .iter_parent_domains()
@@ -113,6 +141,7 @@ pub struct DomainFilter {
     adblock: adblock::engine::Engine,
     allow_regex: regex::RegexSet,
     disallow_regex: regex::RegexSet,
+    block_resource_types: HashSet<String>,
 }
 #[allow(dead_code)]
 impl DomainFilter {
@@ -143,12 +172,27 @@ impl DomainFilter {
         {
             return Some(true);
         }
+        // Only a rule that genuinely blocks the document-level navigation
+        // (or one of the sub-resource types the caller opted into) should
+        // land the whole domain in the blocklist; a `||host^$script` or
+        // `||host^$image` rule must not be read as a blanket host block.
         let url = format!("https://{}", domain);
-        let blocker_result = self.adblock.check_network_urls(&url, &url, "");
-        if blocker_result.exception.is_some() {
+        let mut exception = false;
+        let mut matched = false;
+        for request_type in std::iter::once("document").chain(self.block_resource_types.iter().map(String::as_str))
+        {
+            let blocker_result = self.adblock.check_network_urls(&url, THIRD_PARTY_SOURCE, request_type);
+            if blocker_result.exception.is_some() {
+                exception = true;
+            }
+            if blocker_result.matched {
+                matched = true;
+            }
+        }
+        if exception {
             // Adblock exception rule
             Some(true)
-        } else if blocker_result.matched
+        } else if matched
             || self.disallow_domains.contains(domain)
             || is_subdomain_of_list(&*domain, &self.disallow_subdomains)
             || self.disallow_regex.is_match(domain)
@@ -159,6 +203,13 @@ impl DomainFilter {
         }
     }
 
+    /// The individually disallowed IPs and subnets, for backends (e.g. the
+    /// nftables exporter) that need to render the blocklist outside of the
+    /// `allowed()`/`ip_is_allowed()` matcher.
+    pub fn disallowed_ips(&self) -> (&HashSet<std::net::IpAddr>, &[ipnet::IpNet]) {
+        (&self.disallow_ips, &self.disallow_ip_net)
+    }
+
     fn ip_is_allowed(&self, ip: &std::net::IpAddr) -> Option<bool> {
         if self.allow_ips.contains(ip) || self.allow_ip_net.iter().any(|net| net.contains(ip)) {
             Some(true)
@@ -310,6 +361,52 @@ fn ignores_allowed_ips() {
     )
 }
 
+#[test]
+fn script_only_rule_does_not_block_bare_domain() {
+    let mut filter = DomainFilterBuilder::new();
+    filter.add_adblock_rule("||example.com^$script");
+    let filter = filter.to_domain_filter();
+    assert_eq!(
+        filter.domain_is_allowed(&"example.com".parse().unwrap()),
+        None
+    )
+}
+
+#[test]
+fn block_resource_type_opts_in_a_script_rule() {
+    let mut filter = DomainFilterBuilder::new();
+    filter.add_adblock_rule("||example.com^$script");
+    filter.add_block_resource_type("script");
+    let filter = filter.to_domain_filter();
+    assert_eq!(
+        filter.domain_is_allowed(&"example.com".parse().unwrap()),
+        Some(false)
+    )
+}
+
+#[test]
+fn badfilter_cancels_the_rule_it_targets() {
+    let mut filter = DomainFilterBuilder::new();
+    filter.add_adblock_rule("||example.com^");
+    filter.add_adblock_rule("||example.com^$badfilter");
+    let filter = filter.to_domain_filter();
+    assert_eq!(
+        filter.domain_is_allowed(&"example.com".parse().unwrap()),
+        None
+    )
+}
+
+#[test]
+fn third_party_rule_blocks_since_candidates_have_no_real_page_context() {
+    let mut filter = DomainFilterBuilder::new();
+    filter.add_adblock_rule("||example.com^$third-party");
+    let filter = filter.to_domain_filter();
+    assert_eq!(
+        filter.domain_is_allowed(&"example.com".parse().unwrap()),
+        Some(false)
+    )
+}
+
 #[test]
 fn unblocked_ips_do_not_allow() {
     let mut filter = DomainFilterBuilder::new();
@@ -0,0 +1,104 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+/// A set of CIDR ranges loaded into a sorted, merged interval list so
+/// membership tests over hundreds of thousands of domains stay a binary
+/// search rather than a linear scan of every range.
+#[derive(Default)]
+pub struct IpReputationList {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl IpReputationList {
+    /// Parses each line as a CIDR (or bare IP, treated as a /32 or /128) and
+    /// builds the merged range lists. Unparseable lines are skipped.
+    pub fn from_lines<'a>(lines: impl Iterator<Item = &'a str>) -> Self {
+        let mut v4 = Vec::new();
+        let mut v6 = Vec::new();
+        for line in lines.map(str::trim).filter(|l| !l.is_empty()) {
+            let net: Option<IpNet> = line.parse().ok().or_else(|| {
+                line.parse::<IpAddr>()
+                    .ok()
+                    .map(|ip| IpNet::new(ip, if ip.is_ipv4() { 32 } else { 128 }).unwrap())
+            });
+            match net {
+                Some(IpNet::V4(net)) => v4.push((u32::from(net.network()), u32::from(net.broadcast()))),
+                Some(IpNet::V6(net)) => v6.push((u128::from(net.network()), u128::from(net.broadcast()))),
+                None => {}
+            }
+        }
+        IpReputationList {
+            v4: merge_ranges(v4),
+            v6: merge_ranges(v6),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.v4.is_empty() && self.v6.is_empty()
+    }
+
+    /// Whether `ip` falls inside any of the loaded ranges.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => contains(&self.v4, u32::from(*ip)),
+            IpAddr::V6(ip) => contains(&self.v6, u128::from(*ip)),
+        }
+    }
+
+    /// Whether any of `ips` falls inside a loaded range.
+    pub fn contains_any(&self, ips: &[IpAddr]) -> bool {
+        ips.iter().any(|ip| self.contains(ip))
+    }
+}
+
+fn merge_ranges<T: Ord + Copy>(mut ranges: Vec<(T, T)>) -> Vec<(T, T)> {
+    ranges.sort_unstable_by_key(|r| r.0);
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        if let Some(last) = merged.last_mut() {
+            if start <= last.1 {
+                if end > last.1 {
+                    last.1 = end;
+                }
+                continue;
+            }
+        }
+        merged.push((start, end));
+    }
+    merged
+}
+
+fn contains<T: Ord + Copy>(ranges: &[(T, T)], value: T) -> bool {
+    match ranges.binary_search_by(|&(start, _)| start.cmp(&value)) {
+        Ok(_) => true,
+        Err(0) => false,
+        Err(idx) => ranges[idx - 1].1 >= value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_containing_subnet() {
+        let list = IpReputationList::from_lines(["192.0.2.0/24"].into_iter());
+        assert!(list.contains(&"192.0.2.42".parse().unwrap()));
+        assert!(!list.contains(&"192.0.3.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn merges_overlapping_ranges() {
+        let list = IpReputationList::from_lines(["10.0.0.0/24", "10.0.0.128/25"].into_iter());
+        assert_eq!(list.v4.len(), 1);
+    }
+
+    #[test]
+    fn bare_ip_is_treated_as_host_route() {
+        let list = IpReputationList::from_lines(["203.0.113.7"].into_iter());
+        assert!(list.contains(&"203.0.113.7".parse().unwrap()));
+        assert!(!list.contains(&"203.0.113.8".parse().unwrap()));
+    }
+}
@@ -1,7 +1,15 @@
+mod dns_cache;
+mod dns_lookup;
+mod doh;
+mod domain_filter;
+mod ip_reputation;
+mod nft_export;
+
 use crate::list_downloader::FilterListHandler;
 use clap::Parser;
 use domain_list_builder::*;
 use futures::FutureExt;
+use futures::StreamExt;
 use rand::prelude::*;
 use std::sync::Arc;
 
@@ -24,6 +32,7 @@ enum Mode {
     Generate,
     Query(Query),
     FindDomains(FindDomains),
+    Export(Export),
 }
 #[derive(Parser)]
 struct Query {
@@ -35,6 +44,24 @@ struct Query {
 struct FindDomains {
     #[clap(short, long, default_value = "64")]
     concurrent_requests: std::num::NonZeroUsize,
+    /// Adblock resource types, beyond the always-checked `document`, whose
+    /// rules should also block the whole domain (e.g. `script`, `image`).
+    /// May be passed more than once.
+    #[clap(long = "block-resource-type")]
+    block_resource_types: Vec<String>,
+}
+#[derive(Parser)]
+struct Export {
+    /// Name of the nftables table, in the `inet` family, to add the sets to
+    /// (must already exist)
+    #[clap(long, default_value = "inet")]
+    table: String,
+    #[clap(short, long, default_value = "blockconvert.nft")]
+    output: std::path::PathBuf,
+    /// Also push the sets directly into the kernel over netlink, after
+    /// writing `output`. Requires the `nftables-netlink` feature.
+    #[clap(long)]
+    apply: bool,
 }
 
 const INTERNAL_LISTS: &[(&str, FilterListType)] = &[
@@ -87,7 +114,77 @@ fn read_csv() -> Result<Vec<FilterListRecord>, csv::Error> {
     Ok(records)
 }
 
-async fn generate(mut config: config::Config) -> Result<(), anyhow::Error> {
+/// Builds the combined IP-reputation list `dns_lookup` checks resolved
+/// addresses against, from the same `internal/block_ip*.txt` seed files the
+/// nftables exporter already reads.
+fn load_ip_reputation_list() -> ip_reputation::IpReputationList {
+    let mut lines = Vec::new();
+    if let Ok(data) = std::fs::read_to_string("internal/block_ips.txt") {
+        lines.extend(data.lines().map(str::to_string));
+    }
+    if let Ok(data) = std::fs::read_to_string("internal/block_ipnets.txt") {
+        lines.extend(data.lines().map(str::to_string));
+    }
+    ip_reputation::IpReputationList::from_lines(lines.iter().map(String::as_str))
+}
+
+/// Resolves every domain in `internal/blocklist.txt` through the
+/// SQLite-backed, rate-limited, retrying bulk resolver, dropping only the
+/// domains the resolver can *prove* are dead (an authenticated NXDOMAIN
+/// denial-of-existence). Every other domain survives by default, including
+/// ones that resolved into a reputation-blocklisted range (`dns_lookup`
+/// never passes those to this closure) and ones that merely didn't answer
+/// this round: a timeout, a flaky or rate-limited resolver, or exhausting
+/// `dns_lookup`'s retry budget without ever calling back into this closure
+/// are all ordinary non-answers, not proof a domain is gone, and a single
+/// bad run shouldn't be able to erase real blocklist entries over them.
+async fn prune_dead_domains_from_internal_blocklist(config: &config::Config) -> Result<(), anyhow::Error> {
+    let path = std::path::Path::new("internal/blocklist.txt");
+    let Ok(data) = std::fs::read_to_string(path) else {
+        return Ok(());
+    };
+    let domains: std::collections::HashSet<Domain> =
+        data.lines().filter_map(|l| l.trim().parse().ok()).collect();
+    if domains.is_empty() {
+        return Ok(());
+    }
+    let original_count = domains.len();
+    let survivors = std::sync::Mutex::new(domains.clone());
+
+    let ip_reputation = load_ip_reputation_list();
+    dns_lookup::lookup_domains_with_ttl_bounds(
+        domains,
+        |domain, _cnames, _ips, _ns, _mx, _txt, dead| {
+            if dead {
+                survivors.lock().unwrap().remove(domain);
+            }
+        },
+        &config.get_dns_servers(),
+        config.get_timeout(),
+        config.get_positive_ttl_bounds(),
+        config.get_negative_ttl_bounds(),
+        &ip_reputation,
+        dns_lookup::DEFAULT_RATE_LIMIT_QPS,
+        dns_lookup::DEFAULT_RATE_LIMIT_BURST,
+        dns_lookup::DEFAULT_DISPATCH_STAGGER,
+    )
+    .await
+    .map_err(|error| anyhow::anyhow!("failed to resolve internal/blocklist.txt: {error}"))?;
+
+    let survivors = survivors.into_inner().unwrap();
+    println!(
+        "internal/blocklist.txt: kept {}/{} domains after pruning provably dead ones",
+        survivors.len(),
+        original_count
+    );
+    let rendered = survivors.iter().map(Domain::to_string).collect::<Vec<_>>().join("\n");
+    std::fs::write(path, rendered + "\n")?;
+    Ok(())
+}
+
+async fn generate(mut config: config::Config, db: sled::Db) -> Result<(), anyhow::Error> {
+    promote_malicious_discoveries(&db).await?;
+    prune_dead_domains_from_internal_blocklist(&config).await?;
     let client = reqwest::Client::new();
     if let Ok(records) = read_csv() {
         println!("Read CSV");
@@ -128,6 +225,14 @@ async fn generate(mut config: config::Config) -> Result<(), anyhow::Error> {
             .unwrap();
         println!("Checking DNS");
         let now = std::time::Instant::now();
+        // `FilterList::check_dns` comes from `domain_list_builder` and still
+        // speaks the legacy `application/dns-json` DoH protocol against
+        // whatever resolver the caller's client is pointed at; it isn't
+        // something this crate can swap for the SQLite-backed, rate-limited
+        // `dns_lookup` resolver without a change to that external crate.
+        // `dns_lookup` is wired in wherever blockconvert owns the domain
+        // source end-to-end instead: `internal/blocklist.txt`, pruned above
+        // in `prune_dead_domains_from_internal_blocklist`.
         bc.check_dns(&client).await;
         println!("Checked DNS in {}s", now.elapsed().as_secs_f32());
         println!("Writing to file");
@@ -159,36 +264,34 @@ impl FilterListHandler for QueryFilterListHandler {
     }
 }
 
-async fn query(mut config: config::Config, q: Query) -> Result<(), anyhow::Error> {
-    let mut headers = reqwest::header::HeaderMap::new();
-    headers.insert(
-        reqwest::header::ACCEPT,
-        "application/dns-json".parse().unwrap(),
-    );
-    let client = reqwest::Client::builder()
-        .default_headers(headers)
-        .build()
-        .unwrap();
+async fn query(mut config: config::Config, q: Query, db: sled::Db) -> Result<(), anyhow::Error> {
     let domain = q.query.parse::<Domain>()?;
     let mut parts: Vec<(Domain, Vec<Domain>, Vec<std::net::IpAddr>)> = Vec::new();
     for part in std::iter::once(domain.clone()).chain(domain.iter_parent_domains()) {
         let (cnames, ips): (Vec<Domain>, Vec<std::net::IpAddr>) = if !q.ignore_dns {
-            if let Some(result) = doh::lookup_domain(
+            if let Some(result) = dns_cache::lookup_domain(
+                &db,
                 config
                     .get_dns_servers()
                     .choose(&mut rand::thread_rng())
-                    .unwrap()
-                    .clone(),
-                client.clone(),
-                3_usize,
+                    .unwrap(),
                 config.get_timeout(),
                 &part,
+                config.get_positive_ttl_bounds(),
+                config.get_negative_ttl_bounds(),
             )
             .await?
             {
                 println!("Domain: {:?}", part);
                 println!("CNames: {:?}", result.cnames);
                 println!("IPs: {:?}", result.ips);
+                println!("NS: {:?}", result.ns);
+                println!("MX: {:?}", result.mx);
+                println!("TXT: {:?}", result.txt);
+                println!("DNSSEC: {:?}", result.dnssec);
+                if result.denial_of_existence == doh::DenialOfExistence::Proven {
+                    println!("Authenticated denial of existence: domain is provably dead");
+                }
                 (result.cnames, result.ips)
             } else {
                 Default::default()
@@ -216,14 +319,233 @@ async fn query(mut config: config::Config, q: Query) -> Result<(), anyhow::Error
     Ok(())
 }
 
-async fn find_domains(find_opts: FindDomains, db: sled::Db) -> Result<(), anyhow::Error> {
+fn load_ip_lists_into(builder: &mut domain_filter::DomainFilterBuilder) -> std::io::Result<()> {
+    for line in std::fs::read_to_string("internal/block_ips.txt")?.lines() {
+        if let Ok(ip) = line.trim().parse() {
+            builder.add_disallow_ip_addr(ip);
+        }
+    }
+    for line in std::fs::read_to_string("internal/block_ipnets.txt")?.lines() {
+        if let Ok(net) = line.trim().parse() {
+            builder.add_disallow_ip_subnet(net);
+        }
+    }
+    Ok(())
+}
+
+async fn export(export_opts: Export) -> Result<(), anyhow::Error> {
+    let mut builder = domain_filter::DomainFilterBuilder::new();
+    load_ip_lists_into(&mut builder)?;
+    let filter = builder.to_domain_filter();
+
+    let rendered = nft_export::render_nft_sets(&filter, &export_opts.table);
+    std::fs::write(&export_opts.output, &rendered)?;
+    println!("Wrote nftables sets to {:?}", export_opts.output);
+
+    if export_opts.apply {
+        #[cfg(feature = "nftables-netlink")]
+        nft_export::apply_netlink(&filter, &export_opts.table)?;
+        #[cfg(not(feature = "nftables-netlink"))]
+        println!("Built without the nftables-netlink feature; skipping direct application");
+    }
+    Ok(())
+}
+
+/// Loads the same internal + CSV feeds `generate()` uses into a
+/// `DomainFilter`, so newly discovered domains can be classified the same
+/// way they eventually will be at blocklist-generation time.
+struct DomainFilterHandler {
+    builder: std::sync::Mutex<domain_filter::DomainFilterBuilder>,
+}
+
+impl FilterListHandler for DomainFilterHandler {
+    fn handle_filter_list(&self, record: FilterListRecord, data: &str) {
+        let mut builder = self.builder.lock().unwrap();
+        match record.list_type {
+            FilterListType::DomainBlocklist => {
+                for line in data.lines().filter_map(|l| l.trim().parse().ok()) {
+                    builder.add_disallow_domain(line);
+                }
+            }
+            FilterListType::DomainAllowlist => {
+                for line in data.lines().filter_map(|l| l.trim().parse().ok()) {
+                    builder.add_allow_domain(line);
+                }
+            }
+            FilterListType::Adblock => {
+                for line in data.lines().map(str::trim).filter(|l| !l.is_empty()) {
+                    builder.add_adblock_rule(line);
+                }
+            }
+            FilterListType::RegexBlocklist => {
+                for line in data.lines().map(str::trim).filter(|l| !l.is_empty()) {
+                    builder.add_disallow_regex(line);
+                }
+            }
+            FilterListType::RegexAllowlist => {
+                for line in data.lines().map(str::trim).filter(|l| !l.is_empty()) {
+                    builder.add_allow_regex(line);
+                }
+            }
+            FilterListType::IPBlocklist => {
+                for line in data.lines().filter_map(|l| l.trim().parse().ok()) {
+                    builder.add_disallow_ip_addr(line);
+                }
+            }
+            FilterListType::IPNetBlocklist => {
+                for line in data.lines().filter_map(|l| l.trim().parse().ok()) {
+                    builder.add_disallow_ip_subnet(line);
+                }
+            }
+        }
+    }
+}
+
+async fn build_domain_filter(
+    config: config::Config,
+    block_resource_types: &[String],
+) -> Result<domain_filter::DomainFilter, anyhow::Error> {
+    let records = read_csv()?;
+    let handler = Arc::new(DomainFilterHandler {
+        builder: std::sync::Mutex::new(domain_filter::DomainFilterBuilder::new()),
+    });
+    list_downloader::download_all(
+        config,
+        reqwest::Client::new(),
+        records,
+        get_internal_lists(),
+        handler.clone(),
+    )
+    .await?;
+    let handler = Arc::try_unwrap(handler).ok().expect("Failed to unwrap Arc");
+    let mut builder = handler.builder.into_inner().unwrap();
+    for resource_type in block_resource_types {
+        builder.add_block_resource_type(resource_type);
+    }
+    Ok(builder.to_domain_filter())
+}
+
+/// The classification recorded alongside a CT-discovered domain's resolved
+/// DNS data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Verdict {
+    Allow,
+    Block,
+    Unknown,
+}
+
+impl std::fmt::Display for Verdict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Verdict::Allow => "allow",
+            Verdict::Block => "block",
+            Verdict::Unknown => "unknown",
+        })
+    }
+}
+
+/// A resolved CT-log discovery, persisted to sled so a later `generate` run
+/// can promote newly-seen malicious hosts into `blocklist.txt`.
+struct DiscoveryRecord {
+    cnames: Vec<Domain>,
+    ips: Vec<std::net::IpAddr>,
+    verdict: Verdict,
+}
+
+impl DiscoveryRecord {
+    fn to_bytes(&self) -> Vec<u8> {
+        let cnames = self
+            .cnames
+            .iter()
+            .map(Domain::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
+        let ips = self
+            .ips
+            .iter()
+            .map(std::net::IpAddr::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
+        format!("{};{};{}", cnames, ips, self.verdict).into_bytes()
+    }
+
+    /// The inverse of [`to_bytes`](Self::to_bytes), for reading a discovery
+    /// back out of sled when promoting it.
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let s = std::str::from_utf8(bytes).ok()?;
+        let mut parts = s.splitn(3, ';');
+        let cnames = parts.next()?;
+        let ips = parts.next()?;
+        let verdict = parts.next()?;
+        Some(DiscoveryRecord {
+            cnames: cnames
+                .split(',')
+                .filter(|c| !c.is_empty())
+                .filter_map(|c| c.parse().ok())
+                .collect(),
+            ips: ips
+                .split(',')
+                .filter(|c| !c.is_empty())
+                .filter_map(|ip| ip.parse().ok())
+                .collect(),
+            verdict: match verdict {
+                "allow" => Verdict::Allow,
+                "block" => Verdict::Block,
+                _ => Verdict::Unknown,
+            },
+        })
+    }
+}
+
+/// Merges every CT-discovered domain a prior `find_domains` run verdicted
+/// `Block` into `internal/blocklist.txt`, closing the loop between CT-log
+/// monitoring and blocklist generation: discoveries sit in `db` until the
+/// next `generate` run picks up the ones worth keeping.
+async fn promote_malicious_discoveries(db: &sled::Db) -> Result<(), anyhow::Error> {
+    let path = std::path::Path::new("internal/blocklist.txt");
+    let mut domains: std::collections::HashSet<Domain> = std::fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|l| l.trim().parse().ok())
+        .collect();
+    let before = domains.len();
+
+    for entry in db.iter() {
+        let (key, value) = entry?;
+        let Ok(domain) = std::str::from_utf8(&key).unwrap_or_default().parse::<Domain>() else {
+            continue;
+        };
+        if matches!(DiscoveryRecord::from_bytes(&value), Some(record) if record.verdict == Verdict::Block) {
+            domains.insert(domain);
+        }
+    }
+
+    if domains.len() > before {
+        println!(
+            "Promoted {} CT-discovered domain(s) into internal/blocklist.txt",
+            domains.len() - before
+        );
+        let rendered = domains.iter().map(Domain::to_string).collect::<Vec<_>>().join("\n");
+        std::fs::write(path, rendered + "\n")?;
+    }
+    Ok(())
+}
+
+async fn find_domains(
+    find_opts: FindDomains,
+    config: config::Config,
+    db: sled::Db,
+) -> Result<(), anyhow::Error> {
     println!("Started finding domains");
+    let filter = Arc::new(build_domain_filter(config.clone(), &find_opts.block_resource_types).await?);
+    println!("Built domain filter from internal + CSV feeds");
+
     let (tx, rx) = std::sync::mpsc::channel::<Domain>();
     let db_clone = db.clone();
     let current_lookups = Arc::new(dashmap::DashSet::<Domain>::new());
     let current_lookups_clone = current_lookups.clone();
 
-    let (resolve_tx, mut resolve_rx) = tokio::sync::mpsc::unbounded_channel::<Domain>();
+    let (resolve_tx, resolve_rx) = tokio::sync::mpsc::unbounded_channel::<Domain>();
     std::thread::spawn(move || {
         let current_lookups = current_lookups_clone;
         while let Ok(domain) = rx.recv() {
@@ -239,10 +561,55 @@ async fn find_domains(find_opts: FindDomains, db: sled::Db) -> Result<(), anyhow
             }
         }
     });
-    let dns_task = tokio::task::spawn(async move {
-        while let Some(domain) = resolve_rx.recv().await {
-            println!("Domain: {}", domain);
-            current_lookups.remove(&domain);
+
+    let resolve_rx = tokio_stream::wrappers::UnboundedReceiverStream::new(resolve_rx);
+    let dns_task = tokio::task::spawn({
+        let db = db.clone();
+        let current_lookups = current_lookups.clone();
+        async move {
+            resolve_rx
+                .for_each_concurrent(find_opts.concurrent_requests.get(), |domain| {
+                    let db = db.clone();
+                    let filter = filter.clone();
+                    let current_lookups = current_lookups.clone();
+                    let servers = config.get_dns_servers();
+                    let server = servers.choose(&mut rand::thread_rng()).unwrap().clone();
+                    let timeout = config.get_timeout();
+                    let positive_ttl = config.get_positive_ttl_bounds();
+                    let negative_ttl = config.get_negative_ttl_bounds();
+                    async move {
+                        let result =
+                            dns_cache::lookup_domain(&db, &server, timeout, &domain, positive_ttl, negative_ttl)
+                                .await;
+                        let (cnames, ips) = match result {
+                            Ok(Some(result)) => (result.cnames, result.ips),
+                            _ => Default::default(),
+                        };
+                        // Classify the domain itself and each of its parents;
+                        // a block verdict on any of them (e.g. a malicious
+                        // registrable domain serving this subdomain) wins.
+                        let mut verdict = Verdict::Unknown;
+                        for candidate in std::iter::once(domain.clone()).chain(domain.iter_parent_domains()) {
+                            match filter.allowed(&candidate, &cnames, &ips) {
+                                Some(false) => {
+                                    verdict = Verdict::Block;
+                                    break;
+                                }
+                                Some(true) if verdict == Verdict::Unknown => verdict = Verdict::Allow,
+                                _ => {}
+                            }
+                        }
+                        println!("Domain: {} -> {:?}", domain, verdict);
+                        let record = DiscoveryRecord {
+                            cnames,
+                            ips,
+                            verdict,
+                        };
+                        let _ = db.insert(domain.as_str(), record.to_bytes());
+                        current_lookups.remove(&domain);
+                    }
+                })
+                .await;
         }
     });
     futures::select!(
@@ -269,9 +636,12 @@ async fn main() -> Result<(), anyhow::Error> {
         .open()?;
 
     let result = match opts.mode {
-        Mode::Generate => generate(config::Config::open(opts.config.clone())?).await,
-        Mode::Query(q) => query(config::Config::open(opts.config.clone())?, q).await,
-        Mode::FindDomains(find_opts) => find_domains(find_opts, db).await,
+        Mode::Generate => generate(config::Config::open(opts.config.clone())?, db).await,
+        Mode::Query(q) => query(config::Config::open(opts.config.clone())?, q, db).await,
+        Mode::FindDomains(find_opts) => {
+            find_domains(find_opts, config::Config::open(opts.config.clone())?, db).await
+        }
+        Mode::Export(export_opts) => export(export_opts).await,
     };
     if let Err(error) = &result {
         println!("Failed with error: {:?}", error);
@@ -0,0 +1,168 @@
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+use crate::domain_filter::DomainFilter;
+
+/// Renders the `disallow_ips`/`disallow_ip_net` entries of `filter` as a pair
+/// of ready-to-load nftables named sets (`blockconvert_ipv4`,
+/// `blockconvert_ipv6`), coalescing adjacent and overlapping subnets so the
+/// ruleset stays as small as the data allows.
+pub fn render_nft_sets(filter: &DomainFilter, table: &str) -> String {
+    let (ips, nets) = filter.disallowed_ips();
+    let (v4, v6) = coalesce(ips, nets);
+
+    let mut out = String::new();
+    write_set(&mut out, table, "blockconvert_ipv4", "ipv4_addr", &v4);
+    write_set(&mut out, table, "blockconvert_ipv6", "ipv6_addr", &v6);
+    out
+}
+
+fn write_set(out: &mut String, table: &str, name: &str, set_type: &str, members: &[IpNet]) {
+    if members.is_empty() {
+        return;
+    }
+    let _ = writeln!(out, "add set inet {table} {name} {{ type {set_type}; flags interval; }}");
+    let _ = write!(out, "add element inet {table} {name} {{ ");
+    let rendered: Vec<String> = members.iter().map(|net| net.to_string()).collect();
+    out.push_str(&rendered.join(", "));
+    out.push_str(" }\n");
+}
+
+/// Merges the bare IPs and subnets of a blocklist into non-overlapping
+/// `IpNet`s, split by address family. Sibling subnets are combined into their
+/// shared supernet; adjacent subnets that don't share one stay separate.
+fn coalesce(ips: &HashSet<IpAddr>, nets: &[IpNet]) -> (Vec<IpNet>, Vec<IpNet>) {
+    let mut all: Vec<IpNet> = ips.iter().map(|ip| IpNet::from(*ip)).collect();
+    all.extend(nets.iter().copied());
+
+    let (mut v4, mut v6): (Vec<IpNet>, Vec<IpNet>) = (Vec::new(), Vec::new());
+    for net in all {
+        match net {
+            IpNet::V4(_) => v4.push(net),
+            IpNet::V6(_) => v6.push(net),
+        }
+    }
+    (merge_adjacent(v4), merge_adjacent(v6))
+}
+
+/// Drops subnets already covered by a broader one, then repeatedly merges
+/// same-prefix-length sibling pairs into their parent supernet until nothing
+/// more can be combined.
+fn merge_adjacent(mut nets: Vec<IpNet>) -> Vec<IpNet> {
+    nets.sort_by_key(|n| (n.network(), n.prefix_len()));
+    nets.dedup();
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        nets.sort_by_key(|n| (n.network(), std::cmp::Reverse(n.prefix_len())));
+
+        let mut covered = Vec::with_capacity(nets.len());
+        for net in &nets {
+            if !covered
+                .iter()
+                .any(|existing: &IpNet| existing.contains(net) && existing != net)
+            {
+                covered.push(*net);
+            }
+        }
+        if covered.len() != nets.len() {
+            nets = covered;
+            changed = true;
+            continue;
+        }
+
+        if let Some((a, b, merged)) = nets.windows(2).find_map(|pair| {
+            let (a, b) = (pair[0], pair[1]);
+            let (sa, sb) = (a.supernet()?, b.supernet()?);
+            (sa == sb).then_some((a, b, sa))
+        }) {
+            nets.retain(|n| *n != a && *n != b);
+            nets.push(merged);
+            changed = true;
+        }
+    }
+    nets
+}
+
+/// Pushes the same sets rendered by [`render_nft_sets`] straight into the
+/// kernel over a netlink socket, skipping the intermediate `nft -f` file.
+/// Requires the `nftables-netlink` feature (libnftnl/libmnl).
+#[cfg(feature = "nftables-netlink")]
+pub fn apply_netlink(filter: &DomainFilter, table: &str) -> Result<(), nftnl::error::Error> {
+    use nftnl::{Batch, FinalizedBatch, ProtoFamily};
+
+    let (ips, nets) = filter.disallowed_ips();
+    let (v4, v6) = coalesce(ips, nets);
+
+    let mut batch = Batch::new();
+    let nft_table = nftnl::Table::new(&std::ffi::CString::new(table).unwrap(), ProtoFamily::Inet);
+    batch.add(&nft_table, nftnl::MsgType::Add);
+
+    add_set(&mut batch, &nft_table, "blockconvert_ipv4", nftnl::set::SetKey::Ipv4Addr, &v4);
+    add_set(&mut batch, &nft_table, "blockconvert_ipv6", nftnl::set::SetKey::Ipv6Addr, &v6);
+
+    let batch: FinalizedBatch = batch.finalize();
+    nftnl::send_batch(&batch)
+}
+
+#[cfg(feature = "nftables-netlink")]
+fn add_set(
+    batch: &mut nftnl::Batch,
+    table: &nftnl::Table,
+    name: &str,
+    key_type: nftnl::set::SetKey,
+    members: &[IpNet],
+) {
+    let mut set = nftnl::set::Set::new(
+        &std::ffi::CString::new(name).unwrap(),
+        table,
+        key_type,
+        nftnl::set::SetFlags::INTERVAL,
+    );
+    for net in members {
+        set.add(&net.to_string());
+    }
+    batch.add(&set, nftnl::MsgType::Add);
+}
+
+trait Supernet {
+    fn supernet(&self) -> Option<IpNet>;
+}
+
+impl Supernet for IpNet {
+    fn supernet(&self) -> Option<IpNet> {
+        let prefix_len = self.prefix_len().checked_sub(1)?;
+        IpNet::new(self.network(), prefix_len).ok().map(|n| n.trunc())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_adjacent_same_prefix_subnets_into_their_supernet() {
+        let nets = vec!["192.0.2.0/25".parse().unwrap(), "192.0.2.128/25".parse().unwrap()];
+        assert_eq!(merge_adjacent(nets), vec!["192.0.2.0/24".parse::<IpNet>().unwrap()]);
+    }
+
+    #[test]
+    fn drops_a_subnet_already_covered_by_a_broader_one() {
+        let nets = vec!["192.0.2.0/24".parse().unwrap(), "192.0.2.42/32".parse().unwrap()];
+        assert_eq!(merge_adjacent(nets), vec!["192.0.2.0/24".parse::<IpNet>().unwrap()]);
+    }
+
+    #[test]
+    fn coalesce_splits_results_by_address_family() {
+        let ips: HashSet<IpAddr> = ["198.51.100.1".parse().unwrap(), "2001:db8::1".parse().unwrap()]
+            .into_iter()
+            .collect();
+        let (v4, v6) = coalesce(&ips, &[]);
+        assert_eq!(v4.len(), 1);
+        assert_eq!(v6.len(), 1);
+    }
+}